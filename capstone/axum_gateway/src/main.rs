@@ -1,101 +1,953 @@
-fn main() {
-    println!("Hello from this scaffold. Replace with your implementation.");
-}
-
-// use std::{collections::HashMap, net::SocketAddr, sync::{Arc, atomic::{AtomicU64, Ordering}}};
-// use axum::{routing::{get, post}, Router, response::IntoResponse, extract::{State, ws::{WebSocketUpgrade, Message, WebSocket}}, Json};
-// use tower_http::cors::{Any, CorsLayer};
-// use serde::{Deserialize, Serialize};
-// use tracing::info;
-// use tracing_subscriber::EnvFilter;
-// use tokio::{sync::RwLock, time::{interval, Duration}};
-//
-// #[derive(Clone, Default)]
-// struct AppState {
-//     idempotency: Arc<RwLock<HashMap<String, String>>>,
-//     order_seq: Arc<AtomicU64>,
-// }
-//
-// type Arc<T> = std::sync::Arc<T>;
-//
-// #[derive(Debug, Deserialize)]
-// struct OrderReq { symbol: String, side: String, qty: u64, #[serde(default)] r#type: String, #[serde(default)] price: Option<f64>, #[serde(default)] client_id: Option<String> }
-//
-// #[derive(Debug, Serialize)]
-// struct OrderResp { status: String, order_id: String }
-//
-// #[tokio::main]
-// async fn main() -> anyhow::Result<()> {
-//     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-//     tracing_subscriber::fmt().with_env_filter(filter).with_target(false).init();
-//
-//     let state = AppState { idempotency: Arc::new(RwLock::new(HashMap::new())), order_seq: Arc::new(AtomicU64::new(0)) };
-//
-//     let app = Router::new()
-//         .route("/health", get(|| async { Json(serde_json::json!({ "status":"ok" })) }))
-//         .route("/metrics", get(metrics))
-//         .route("/orders", post(orders))
-//         .route("/cancel", post(cancel))
-//         .route("/ws/feed", get(ws_feed))
-//         .with_state(state)
-//         .layer(CorsLayer::new().allow_methods(Any).allow_headers(Any).allow_origin(Any));
-//
-//     let addr: SocketAddr = "0.0.0.0:8080".parse()?;
-//     info!("Gateway on http://{addr}  |  WS: ws://{addr}/ws/feed  |  POST /orders  |  GET /metrics  |  GET /health");
-//     axum::Server::bind(&addr).serve(app.into_make_service()).await?;
-//     Ok(())
-// }
-//
-// async fn metrics() -> impl IntoResponse {
-//     ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], "# HELP demo 1\n# TYPE demo counter\ndemo 1\n")
-// }
-//
-// async fn orders(
-//     State(state): State<AppState>,
-//     headers: axum::http::HeaderMap,
-//     Json(_req): Json<OrderReq>
-// ) -> impl IntoResponse {
-//     let key = headers.get("x-idempotency-key").and_then(|v| v.to_str().ok()).map(|s| s.to_string());
-//     let mut idemp = state.idempotency.write().await;
-//     if let Some(k) = key.clone() {
-//         if let Some(existing) = idemp.get(&k) {
-//             return Json(serde_json::json!({ "status":"duplicate", "order_id": existing }));
-//         }
-//     }
-//     let next = state.order_seq.fetch_add(1, Ordering::Relaxed) + 1;
-//     let oid = format!("ord_{:08}", next);
-//     if let Some(k) = key { idemp.insert(k, oid.clone()); }
-//     Json(serde_json::json!(OrderResp{ status:"accepted".into(), order_id: oid }))
-// }
-//
-// #[derive(Debug, Deserialize)]
-// struct CancelReq { order_id: String }
-// async fn cancel(Json(req): Json<CancelReq>) -> impl IntoResponse {
-//     Json(serde_json::json!({ "status":"cancelled", "order_id": req.order_id }))
-// }
-//
-// async fn ws_feed(ws: WebSocketUpgrade) -> impl IntoResponse { ws.on_upgrade(handle_socket) }
-// async fn handle_socket(mut socket: WebSocket) {
-//     use axum::extract::ws::Message::*;
-//     if let Some(Ok(Text(txt))) = socket.recv().await { tracing::info!("Client said: {txt}"); }
-//     let snapshot = serde_json::json!({
-//         "type":"snapshot","v":"1.0","symbol":"DEMO",
-//         "bids":[[100.0,500],[99.9,800]],"asks":[[100.1,450],[100.2,900]],
-//         "ts": now_ms()
-//     }).to_string();
-//     if socket.send(Message::Text(snapshot)).await.is_err() { return; }
-//     let mut price = 100.0; let mut qty: i64 = 500; let mut iv = interval(Duration::from_millis(200));
-//     loop {
-//         iv.tick().await;
-//         price += if now_ms() % 2 == 0 { 0.02 } else { -0.02 };
-//         qty += if now_ms() % 2 == 0 { 20 } else { -20 };
-//         if qty < 0 { qty = 100; }
-//         let update = serde_json::json!({
-//             "type":"l2_update","v":"1.0","symbol":"DEMO",
-//             "side": if now_ms()%2==0 {"bid"} else {"ask"},
-//             "price": (price*100.0).round()/100.0,"delta": qty,"ts": now_ms()
-//         }).to_string();
-//         if socket.send(Message::Text(update)).await.is_err() { break; }
-//     }
-// }
-// fn now_ms() -> u128 { use std::time::{SystemTime, UNIX_EPOCH}; SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() }
+use axum::{
+    extract::{
+        rejection::JsonRejection,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    sync::{broadcast, watch, RwLock},
+    time::{interval, Duration, Instant},
+};
+use tower_http::cors::{Any, CorsLayer};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+/// Buffered order events per `/ws/feed` subscriber before it starts lagging.
+const FEED_CAPACITY: usize = 1024;
+
+/// How long shutdown waits for open `/ws/feed` sockets to finish closing.
+const WS_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default lifetime of an idempotency key when `IDEMPOTENCY_TTL_SECS` is unset.
+const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(300);
+
+/// Default lifetime of a resting order when `OPEN_ORDER_TTL_SECS` is unset.
+const DEFAULT_OPEN_ORDER_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone)]
+struct AppState {
+    idempotency: Arc<RwLock<HashMap<String, IdempotencyEntry>>>,
+    order_seq: Arc<AtomicU64>,
+    idempotency_ttl: Duration,
+    metrics: Arc<Metrics>,
+    open_orders: Arc<RwLock<HashMap<String, OpenOrder>>>,
+    open_order_ttl: Duration,
+    feed: broadcast::Sender<L2Update>,
+    /// Flipped to `true` on shutdown so open feed sockets can close cleanly.
+    shutdown: watch::Sender<bool>,
+}
+
+/// Book change published to `/ws/feed` subscribers when a limit order is accepted or removed.
+#[derive(Debug, Clone, Serialize)]
+struct L2Update {
+    symbol: String,
+    side: &'static str,
+    price: f64,
+    delta: i64,
+}
+
+/// Resting order that can still be cancelled, removed from the book at `expires_at`.
+#[derive(Debug, Clone)]
+struct OpenOrder {
+    update: L2Update,
+    expires_at: Instant,
+}
+
+/// Counters exported on `/metrics` in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+struct Metrics {
+    orders_accepted: AtomicU64,
+    orders_duplicate: AtomicU64,
+    cancels: AtomicU64,
+}
+
+/// Order id remembered for an idempotency key, valid until `expires_at`.
+#[derive(Debug, Clone)]
+struct IdempotencyEntry {
+    order_id: String,
+    expires_at: Instant,
+}
+
+impl AppState {
+    fn new(idempotency_ttl: Duration, open_order_ttl: Duration) -> Self {
+        Self {
+            idempotency: Arc::new(RwLock::new(HashMap::new())),
+            order_seq: Arc::new(AtomicU64::new(0)),
+            idempotency_ttl,
+            metrics: Arc::new(Metrics::default()),
+            open_orders: Arc::new(RwLock::new(HashMap::new())),
+            open_order_ttl,
+            feed: broadcast::channel(FEED_CAPACITY).0,
+            shutdown: watch::channel(false).0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OrderReq {
+    symbol: String,
+    side: String,
+    qty: u64,
+    #[serde(default)]
+    r#type: String,
+    #[serde(default)]
+    price: Option<f64>,
+    #[serde(default)]
+    #[allow(dead_code)]
+    client_id: Option<String>,
+}
+
+impl OrderReq {
+    /// Checks field-level invariants, returning the offending field and a reason on failure.
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if self.side != "buy" && self.side != "sell" {
+            return Err((
+                "side",
+                format!("side must be \"buy\" or \"sell\", got {:?}", self.side),
+            ));
+        }
+        if self.qty == 0 {
+            return Err(("qty", "qty must be greater than zero".into()));
+        }
+        // Feed deltas are signed, so qty has to fit in an i64 to be published and later negated.
+        if i64::try_from(self.qty).is_err() {
+            return Err(("qty", format!("qty must be at most {}", i64::MAX)));
+        }
+        if self.r#type == "limit" {
+            match self.price {
+                Some(p) if p.is_finite() && p > 0.0 => {}
+                Some(p) => return Err(("price", format!("limit price must be positive, got {p}"))),
+                None => return Err(("price", "limit orders require a price".into())),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OrderResp {
+    status: String,
+    order_id: String,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .init();
+
+    let state = AppState::new(
+        ttl_from_env("IDEMPOTENCY_TTL_SECS", DEFAULT_IDEMPOTENCY_TTL)?,
+        ttl_from_env("OPEN_ORDER_TTL_SECS", DEFAULT_OPEN_ORDER_TTL)?,
+    );
+    tokio::spawn(sweep_idempotency(state.clone()));
+    tokio::spawn(sweep_open_orders(state.clone()));
+
+    let addr: SocketAddr = "0.0.0.0:8080".parse()?;
+    info!("Gateway on http://{addr}  |  WS: ws://{addr}/ws/feed  |  POST /orders  |  GET /metrics  |  GET /health");
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let shutdown = state.shutdown.clone();
+    let trigger = shutdown.clone();
+    axum::serve(listener, app(state))
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            info!("Shutdown signal received, draining connections");
+            trigger.send_replace(true);
+        })
+        .await?;
+    // Upgraded feed sockets run outside axum's graceful shutdown. Each one holds a watch
+    // receiver, so the channel closes once every socket task has sent its Close frame.
+    if tokio::time::timeout(WS_DRAIN_TIMEOUT, shutdown.closed())
+        .await
+        .is_err()
+    {
+        tracing::warn!("feed sockets still open after {WS_DRAIN_TIMEOUT:?}, exiting anyway");
+    }
+    info!("Gateway stopped");
+    Ok(())
+}
+
+/// Resolves on ctrl-c or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("failed to listen for ctrl-c: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+fn app(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/health",
+            get(|| async { Json(serde_json::json!({ "status":"ok" })) }),
+        )
+        .route("/metrics", get(metrics))
+        .route("/orders", post(orders))
+        .route("/cancel", post(cancel))
+        .route("/ws/feed", get(ws_feed))
+        .with_state(state)
+        .layer(
+            CorsLayer::new()
+                .allow_methods(Any)
+                .allow_headers(Any)
+                .allow_origin(Any),
+        )
+}
+
+/// Reads a TTL in whole seconds from `var`, falling back to `default` when it is unset.
+fn ttl_from_env(var: &str, default: Duration) -> anyhow::Result<Duration> {
+    match std::env::var(var) {
+        Ok(raw) => {
+            let secs: u64 = raw
+                .trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid {var} {raw:?}: {e}"))?;
+            anyhow::ensure!(secs > 0, "{var} must be greater than zero");
+            Ok(Duration::from_secs(secs))
+        }
+        Err(_) => Ok(default),
+    }
+}
+
+/// Periodically evicts expired idempotency keys so the map stays bounded under sustained traffic.
+async fn sweep_idempotency(state: AppState) {
+    let mut iv = interval(state.idempotency_ttl.min(Duration::from_secs(30)));
+    loop {
+        iv.tick().await;
+        let now = Instant::now();
+        let mut idemp = state.idempotency.write().await;
+        let before = idemp.len();
+        idemp.retain(|_, entry| entry.expires_at > now);
+        let evicted = before - idemp.len();
+        if evicted > 0 {
+            tracing::debug!("evicted {evicted} expired idempotency keys");
+        }
+    }
+}
+
+/// Periodically removes resting orders past their TTL and publishes their removal to the feed.
+///
+/// Nothing fills orders in this gateway, so without expiry every uncancelled order would stay
+/// in memory forever.
+async fn sweep_open_orders(state: AppState) {
+    let mut iv = interval(state.open_order_ttl.min(Duration::from_secs(30)));
+    loop {
+        iv.tick().await;
+        let now = Instant::now();
+        let mut open = state.open_orders.write().await;
+        let before = open.len();
+        open.retain(|_, order| {
+            if order.expires_at > now {
+                return true;
+            }
+            let _ = state.feed.send(L2Update {
+                delta: -order.update.delta,
+                ..order.update.clone()
+            });
+            false
+        });
+        let expired = before - open.len();
+        if expired > 0 {
+            tracing::debug!("expired {expired} resting orders");
+        }
+    }
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let m = &state.metrics;
+    // Expired keys may linger until the next sweep; only count the ones still honoured.
+    let now = Instant::now();
+    let idempotency_keys = state
+        .idempotency
+        .read()
+        .await
+        .values()
+        .filter(|entry| entry.expires_at > now)
+        .count();
+    let mut body = String::new();
+    for (name, kind, help, value) in [
+        (
+            "gateway_orders_accepted_total",
+            "counter",
+            "Orders accepted by the gateway.",
+            m.orders_accepted.load(Ordering::Relaxed),
+        ),
+        (
+            "gateway_orders_duplicate_total",
+            "counter",
+            "Orders answered from a known idempotency key.",
+            m.orders_duplicate.load(Ordering::Relaxed),
+        ),
+        (
+            "gateway_cancels_total",
+            "counter",
+            "Cancel requests handled.",
+            m.cancels.load(Ordering::Relaxed),
+        ),
+        (
+            "gateway_idempotency_keys",
+            "gauge",
+            "Unexpired idempotency keys currently held in memory.",
+            idempotency_keys as u64,
+        ),
+    ] {
+        body.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ));
+    }
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        body,
+    )
+}
+
+async fn orders(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    payload: Result<Json<OrderReq>, JsonRejection>,
+) -> Response {
+    let req = match payload {
+        Ok(Json(req)) => req,
+        Err(rejection) => {
+            let (field, error) = rejected_field(&rejection);
+            return reject(field, error);
+        }
+    };
+    if let Err((field, error)) = req.validate() {
+        return reject(field, error);
+    }
+    let key = headers
+        .get("x-idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+    let mut idemp = state.idempotency.write().await;
+    let now = Instant::now();
+    if let Some(k) = key.as_ref() {
+        // The sweeper runs on an interval, so an expired key may still be present; treat it as new.
+        if let Some(existing) = idemp.get(k).filter(|e| e.expires_at > now) {
+            state
+                .metrics
+                .orders_duplicate
+                .fetch_add(1, Ordering::Relaxed);
+            return Json(
+                serde_json::json!({ "status":"duplicate", "order_id": existing.order_id }),
+            )
+            .into_response();
+        }
+    }
+    let next = state.order_seq.fetch_add(1, Ordering::Relaxed) + 1;
+    let oid = format!("ord_{:08}", next);
+    state
+        .metrics
+        .orders_accepted
+        .fetch_add(1, Ordering::Relaxed);
+    if let Some(k) = key {
+        idemp.insert(
+            k,
+            IdempotencyEntry {
+                order_id: oid.clone(),
+                expires_at: now + state.idempotency_ttl,
+            },
+        );
+    }
+    drop(idemp);
+    // Only limit orders rest on the book; anything else never reaches the feed.
+    if req.r#type == "limit" {
+        let update = L2Update {
+            symbol: req.symbol,
+            side: if req.side == "buy" { "bid" } else { "ask" },
+            price: req
+                .price
+                .expect("validate() requires a price for limit orders"),
+            delta: i64::try_from(req.qty).expect("validate() bounds qty to i64"),
+        };
+        state.open_orders.write().await.insert(
+            oid.clone(),
+            OpenOrder {
+                update: update.clone(),
+                expires_at: now + state.open_order_ttl,
+            },
+        );
+        // A send error only means no feed client is connected right now.
+        let _ = state.feed.send(update);
+    }
+    Json(serde_json::json!(OrderResp {
+        status: "accepted".into(),
+        order_id: oid
+    }))
+    .into_response()
+}
+
+fn reject(field: impl Into<String>, error: String) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({ "status":"rejected", "field": field.into(), "error": error })),
+    )
+        .into_response()
+}
+
+/// Names the field behind a body that failed to deserialize, or `"body"` if it is not field-specific.
+fn rejected_field(rejection: &JsonRejection) -> (String, String) {
+    // axum wraps the serde_path_to_error failure a couple of layers down the source chain.
+    let mut source = std::error::Error::source(rejection);
+    while let Some(err) = source {
+        if let Some(e) = err.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>() {
+            let error = e.inner().to_string();
+            let path = e.path().to_string();
+            // Missing fields are reported against the parent, so pull the name out of the message.
+            let field = match error.strip_prefix("missing field `") {
+                Some(rest) if path == "." => rest.split('`').next().unwrap_or(rest).to_string(),
+                // Syntax errors have no usable path ("?" or the root ".").
+                _ if path == "?" || path == "." => "body".into(),
+                _ => path,
+            };
+            return (field, error);
+        }
+        source = err.source();
+    }
+    ("body".into(), rejection.body_text())
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelReq {
+    order_id: String,
+}
+async fn cancel(State(state): State<AppState>, Json(req): Json<CancelReq>) -> Response {
+    state.metrics.cancels.fetch_add(1, Ordering::Relaxed);
+    let Some(order) = state.open_orders.write().await.remove(&req.order_id) else {
+        // Covers ids never issued, already cancelled, expired, and non-resting (market) orders.
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "status":"not_found", "order_id": req.order_id,
+                "error": "no open order with this id; it may be unknown, cancelled or expired"
+            })),
+        )
+            .into_response();
+    };
+    let _ = state.feed.send(L2Update {
+        delta: -order.update.delta,
+        ..order.update
+    });
+    Json(serde_json::json!({ "status":"cancelled", "order_id": req.order_id })).into_response()
+}
+
+async fn ws_feed(State(state): State<AppState>, ws: WebSocketUpgrade) -> impl IntoResponse {
+    let events = state.feed.subscribe();
+    let shutdown = state.shutdown.subscribe();
+    ws.on_upgrade(move |socket| handle_socket(socket, events, shutdown))
+}
+async fn handle_socket(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<L2Update>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    use axum::extract::ws::Message::*;
+    tokio::select! {
+        _ = stopping(&mut shutdown) => {
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+        first = socket.recv() => {
+            if let Some(Ok(Text(txt))) = first {
+                tracing::info!("Client said: {txt}");
+            }
+        }
+    }
+    let snapshot = serde_json::json!({
+        "type":"snapshot","v":"1.0","symbol":"DEMO",
+        "bids":[[100.0,500],[99.9,800]],"asks":[[100.1,450],[100.2,900]],
+        "ts": now_ms()
+    })
+    .to_string();
+    if socket.send(Message::Text(snapshot)).await.is_err() {
+        return;
+    }
+    loop {
+        tokio::select! {
+            _ = stopping(&mut shutdown) => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+            event = events.recv() => match event {
+                Ok(ev) => {
+                    let update = serde_json::json!({
+                        "type":"l2_update","v":"1.0","symbol": ev.symbol,
+                        "side": ev.side,"price": ev.price,"delta": ev.delta,"ts": now_ms()
+                    })
+                    .to_string();
+                    if socket.send(Message::Text(update)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    // The client's book is missing updates; make it reconnect for a fresh snapshot.
+                    tracing::warn!("feed client lagged, dropped {skipped} updates; closing");
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::AGAIN,
+                            reason: "feed lagged, reconnect for a fresh snapshot".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+/// Resolves once shutdown is signalled. The watch guard is dropped here because it is not `Send`.
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stopping| *stopping).await;
+}
+fn now_ms() -> u128 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use futures_util::{SinkExt, StreamExt};
+    use http_body_util::BodyExt;
+    use tokio_tungstenite::tungstenite;
+    use tower::ServiceExt;
+
+    type FeedClient = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Serves the router on an ephemeral port and opens a `/ws/feed` client against it.
+    async fn connect_feed(state: &AppState) -> FeedClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = app(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws/feed"))
+            .await
+            .unwrap();
+        ws
+    }
+
+    async fn next_frame(ws: &mut FeedClient) -> tungstenite::Message {
+        tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("timed out waiting for a feed frame")
+            .expect("feed closed without a frame")
+            .unwrap()
+    }
+
+    async fn next_json(ws: &mut FeedClient) -> serde_json::Value {
+        match next_frame(ws).await {
+            tungstenite::Message::Text(txt) => serde_json::from_str(&txt).unwrap(),
+            other => panic!("expected a text frame, got {other:?}"),
+        }
+    }
+
+    async fn order_with_key(state: &AppState, key: &str) -> serde_json::Value {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert("x-idempotency-key", key.parse().unwrap());
+        let req =
+            serde_json::from_value(serde_json::json!({ "symbol":"DEMO","side":"buy","qty":1 }))
+                .unwrap();
+        let resp = orders(State(state.clone()), headers, Ok(Json(req)))
+            .await
+            .into_response();
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idempotency_keys_expire_after_ttl() {
+        let ttl = Duration::from_secs(10);
+        let state = AppState::new(ttl, DEFAULT_OPEN_ORDER_TTL);
+        tokio::spawn(sweep_idempotency(state.clone()));
+
+        let first = order_with_key(&state, "k").await;
+        assert_eq!(first["status"], "accepted");
+        let repeat = order_with_key(&state, "k").await;
+        assert_eq!(repeat["status"], "duplicate");
+        assert_eq!(repeat["order_id"], first["order_id"]);
+
+        tokio::time::advance(ttl).await;
+        let renewed = order_with_key(&state, "k").await;
+        assert_eq!(renewed["status"], "accepted");
+        assert_ne!(renewed["order_id"], first["order_id"]);
+
+        // Let the sweeper tick once more after the renewed key has expired.
+        tokio::time::advance(ttl).await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(!state.idempotency.read().await.contains_key("k"));
+    }
+
+    async fn post(
+        state: &AppState,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let req = Request::post(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    async fn get_text(state: &AppState, uri: &str) -> String {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    async fn post_order(body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        post(
+            &AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL),
+            "/orders",
+            body,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_limit_order() {
+        let (status, body) = post_order(serde_json::json!({
+            "symbol":"DEMO","side":"buy","qty":100,"type":"limit","price":100.0
+        }))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "accepted");
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_side() {
+        let (status, body) =
+            post_order(serde_json::json!({ "symbol":"DEMO","side":"hold","qty":1 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "side");
+    }
+
+    #[tokio::test]
+    async fn rejects_zero_qty() {
+        let (status, body) =
+            post_order(serde_json::json!({ "symbol":"DEMO","side":"sell","qty":0 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "qty");
+    }
+
+    #[tokio::test]
+    async fn rejects_negative_qty_as_bad_request() {
+        let (status, body) =
+            post_order(serde_json::json!({ "symbol":"DEMO","side":"buy","qty":-5 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], "rejected");
+        assert_eq!(body["field"], "qty");
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_side_as_bad_request() {
+        let (status, body) = post_order(serde_json::json!({ "symbol":"DEMO","qty":1 })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "side");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_json_as_bad_request() {
+        let req = Request::post("/orders")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"symbol":"DEMO","side":"buy""#))
+            .unwrap();
+        let resp = app(AppState::new(
+            DEFAULT_IDEMPOTENCY_TTL,
+            DEFAULT_OPEN_ORDER_TTL,
+        ))
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["field"], "body");
+    }
+
+    #[tokio::test]
+    async fn rejects_limit_without_positive_price() {
+        for price in [serde_json::Value::Null, serde_json::json!(-1.0)] {
+            let (status, body) = post_order(serde_json::json!({
+                "symbol":"DEMO","side":"buy","qty":1,"type":"limit","price":price
+            }))
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["field"], "price");
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_accept_and_cancel_to_feed() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL);
+        let mut feed = state.feed.subscribe();
+        let (_, body) = post(
+            &state,
+            "/orders",
+            serde_json::json!({ "symbol":"DEMO","side":"sell","qty":5,"type":"limit","price":101.5 }),
+        )
+        .await;
+        let accepted = feed.recv().await.unwrap();
+        assert_eq!((accepted.side, accepted.delta), ("ask", 5));
+        assert_eq!(accepted.price, 101.5);
+
+        post(
+            &state,
+            "/cancel",
+            serde_json::json!({ "order_id": body["order_id"] }),
+        )
+        .await;
+        let cancelled = feed.recv().await.unwrap();
+        assert_eq!((cancelled.side, cancelled.delta), ("ask", -5));
+    }
+
+    #[tokio::test]
+    async fn feed_socket_sends_snapshot_then_l2_updates() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL);
+        let mut ws = connect_feed(&state).await;
+        ws.send(tungstenite::Message::Text("hello".into()))
+            .await
+            .unwrap();
+        let snapshot = next_json(&mut ws).await;
+        assert_eq!(snapshot["type"], "snapshot");
+
+        let (_, body) = post(
+            &state,
+            "/orders",
+            serde_json::json!({ "symbol":"DEMO","side":"buy","qty":4,"type":"limit","price":99.5 }),
+        )
+        .await;
+        let update = next_json(&mut ws).await;
+        assert_eq!(update["type"], "l2_update");
+        assert_eq!(update["v"], "1.0");
+        assert_eq!(update["symbol"], "DEMO");
+        assert_eq!(update["side"], "bid");
+        assert_eq!(update["price"], 99.5);
+        assert_eq!(update["delta"], 4);
+        assert!(update["ts"].is_u64());
+
+        post(
+            &state,
+            "/cancel",
+            serde_json::json!({ "order_id": body["order_id"] }),
+        )
+        .await;
+        let removal = next_json(&mut ws).await;
+        assert_eq!(removal["type"], "l2_update");
+        assert_eq!(removal["delta"], -4);
+    }
+
+    #[tokio::test]
+    async fn feed_closes_lagging_clients() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL);
+        let mut ws = connect_feed(&state).await;
+        // The socket task is parked on the client's first message, so these pile up unread.
+        for _ in 0..=FEED_CAPACITY {
+            let _ = state.feed.send(L2Update {
+                symbol: "DEMO".into(),
+                side: "bid",
+                price: 100.0,
+                delta: 1,
+            });
+        }
+        ws.send(tungstenite::Message::Text("hello".into()))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "snapshot");
+        match next_frame(&mut ws).await {
+            tungstenite::Message::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), close_code::AGAIN);
+            }
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+    }
+
+    /// Flips the shutdown flag and checks the socket is closed and its task lets go of the feed.
+    async fn assert_closed_on_shutdown(state: &AppState, mut ws: FeedClient) {
+        state.shutdown.send_replace(true);
+        match next_frame(&mut ws).await {
+            tungstenite::Message::Close(_) => {}
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+        // The client stays connected, so only the server side can release the receiver.
+        tokio::time::timeout(Duration::from_secs(5), state.shutdown.closed())
+            .await
+            .expect("feed socket task outlived shutdown");
+        drop(ws);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_feed_waiting_for_first_message() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL);
+        let ws = connect_feed(&state).await;
+        assert_closed_on_shutdown(&state, ws).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_streaming_feed() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL);
+        let mut ws = connect_feed(&state).await;
+        ws.send(tungstenite::Message::Text("hello".into()))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut ws).await["type"], "snapshot");
+        assert_closed_on_shutdown(&state, ws).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_qty_beyond_i64() {
+        let (status, body) = post_order(serde_json::json!({
+            "symbol":"DEMO","side":"buy","qty":9_223_372_036_854_775_808u64,"type":"limit","price":1.0
+        }))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "qty");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_resting_orders_and_publishes_removal() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, Duration::from_secs(10));
+        let mut feed = state.feed.subscribe();
+        tokio::spawn(sweep_open_orders(state.clone()));
+        let (_, body) = post(
+            &state,
+            "/orders",
+            serde_json::json!({ "symbol":"DEMO","side":"buy","qty":3,"type":"limit","price":99.0 }),
+        )
+        .await;
+        assert_eq!(feed.recv().await.unwrap().delta, 3);
+
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(feed.recv().await.unwrap().delta, -3);
+        assert!(state.open_orders.read().await.is_empty());
+
+        let (status, body) = post(
+            &state,
+            "/cancel",
+            serde_json::json!({ "order_id": body["order_id"] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn cancel_reports_unknown_orders() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL);
+        let mut feed = state.feed.subscribe();
+        let (status, body) = post(
+            &state,
+            "/cancel",
+            serde_json::json!({ "order_id": "ord_99999999" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], "not_found");
+        assert!(matches!(
+            feed.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    #[tokio::test]
+    async fn market_orders_do_not_reach_the_feed() {
+        let state = AppState::new(DEFAULT_IDEMPOTENCY_TTL, DEFAULT_OPEN_ORDER_TTL);
+        let mut feed = state.feed.subscribe();
+        let (status, _) = post(
+            &state,
+            "/orders",
+            serde_json::json!({ "symbol":"DEMO","side":"buy","qty":7,"type":"market" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(matches!(
+            feed.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+        assert!(state.open_orders.read().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn metrics_track_orders_duplicates_and_cancels() {
+        let ttl = Duration::from_secs(10);
+        let state = AppState::new(ttl, DEFAULT_OPEN_ORDER_TTL);
+        let before = get_text(&state, "/metrics").await;
+        assert!(before.contains("gateway_orders_accepted_total 0\n"));
+        assert!(before.contains("gateway_idempotency_keys 0\n"));
+
+        let accepted = order_with_key(&state, "k").await;
+        order_with_key(&state, "k").await;
+        post(
+            &state,
+            "/cancel",
+            serde_json::json!({ "order_id": accepted["order_id"] }),
+        )
+        .await;
+
+        let after = get_text(&state, "/metrics").await;
+        assert!(after.contains("gateway_orders_accepted_total 1\n"));
+        assert!(after.contains("gateway_orders_duplicate_total 1\n"));
+        assert!(after.contains("gateway_cancels_total 1\n"));
+        assert!(after.contains("gateway_idempotency_keys 1\n"));
+
+        // No sweeper is running, so the expired key is still in the map but not counted.
+        tokio::time::advance(ttl).await;
+        let expired = get_text(&state, "/metrics").await;
+        assert!(expired.contains("gateway_idempotency_keys 0\n"));
+        assert_eq!(state.idempotency.read().await.len(), 1);
+    }
+}